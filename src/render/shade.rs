@@ -13,8 +13,19 @@
 // limitations under the License.
 
 //! Shader parameter handling.
-
-use std::cell::Cell;
+//!
+//! TODO(chunk0-5): the `device_gl`-side half of a compiled-program binary
+//! cache - calling `glGetProgramBinary`, reading/writing the blob, a
+//! `DeviceHelper::create_program_from_binary(format, &[u8])` loader, and a
+//! fallback recompile-from-source path when the driver rejects a cached
+//! binary - is not implemented here, and can't be: none of `DeviceHelper`,
+//! `ProgramHandle` construction, or the GL calls are part of this checkout.
+//! That's still a separate PR against `device_gl`. `CachedProgram` below is
+//! the part of the cache that *is* in scope here: what has to travel with
+//! the blob so `ShaderParam::create_link` can link against the restored
+//! program without re-reflecting the shader source.
+
+use std::cell::RefCell;
 use std::rc::Rc;
 use device::shade as s;
 use device::{RawBufferHandle, ProgramHandle, SamplerHandle, TextureHandle};
@@ -25,6 +36,12 @@ pub trait ToUniform {
     fn to_uniform(&self) -> s::UniformValue;
 }
 
+// TODO(chunk0-3): `s::ValueU32`/`s::ValueBool` (and their vectors) are only
+// wired up to here. The GL backend's uniform upload (the glUniform* match in
+// device_gl) needs matching arms for them too, or a value produced through
+// this trait for those types is silently never sent to the driver. That
+// backend lives in the device_gl crate, not part of this checkout.
+
 macro_rules! impl_ToUniform(
     ($srcty:ty, $dstty:expr) => (
         impl ToUniform for $srcty {
@@ -36,20 +53,65 @@ macro_rules! impl_ToUniform(
 )
 
 impl_ToUniform!(i32, s::ValueI32)
+impl_ToUniform!(u32, s::ValueU32)
 impl_ToUniform!(f32, s::ValueF32)
+impl_ToUniform!(bool, s::ValueBool)
 
 impl_ToUniform!([i32, ..2], s::ValueI32Vector2)
 impl_ToUniform!([i32, ..3], s::ValueI32Vector3)
 impl_ToUniform!([i32, ..4], s::ValueI32Vector4)
 
+impl_ToUniform!([u32, ..2], s::ValueU32Vector2)
+impl_ToUniform!([u32, ..3], s::ValueU32Vector3)
+impl_ToUniform!([u32, ..4], s::ValueU32Vector4)
+
 impl_ToUniform!([f32, ..2], s::ValueF32Vector2)
 impl_ToUniform!([f32, ..3], s::ValueF32Vector3)
 impl_ToUniform!([f32, ..4], s::ValueF32Vector4)
 
+impl_ToUniform!([bool, ..2], s::ValueBoolVector2)
+impl_ToUniform!([bool, ..3], s::ValueBoolVector3)
+impl_ToUniform!([bool, ..4], s::ValueBoolVector4)
+
 impl_ToUniform!([[f32, ..2], ..2], s::ValueF32Matrix2)
 impl_ToUniform!([[f32, ..3], ..3], s::ValueF32Matrix3)
 impl_ToUniform!([[f32, ..4], ..4], s::ValueF32Matrix4)
 
+/// Uniform arrays, e.g. `uniform vec4 bones[64]`, addressed via `Rc<Vec<T>>`
+/// so a large per-frame palette (e.g. 64 bone matrices) is shared by a cheap
+/// refcount bump on every `fill_params` call instead of being deep-cloned.
+/// The declared array size is checked against the `Vec`'s length at link time.
+macro_rules! impl_ToUniform_array(
+    ($elemty:ty, $dstty:expr) => (
+        impl ToUniform for Rc<Vec<$elemty>> {
+            fn to_uniform(&self) -> s::UniformValue {
+                $dstty(self.clone())
+            }
+        }
+    );
+)
+
+impl_ToUniform_array!(i32, s::ValueI32Array)
+impl_ToUniform_array!([f32, ..4], s::ValueF32Vector4Array)
+impl_ToUniform_array!([[f32, ..4], ..4], s::ValueF32Matrix4Array)
+
+/// A linked program's `glGetProgramBinary` blob, plus everything
+/// `ShaderParam::create_link` needs that would otherwise come from
+/// re-reflecting the shader source. Persisting `info` alongside `binary`
+/// means a program restored from a cache links exactly like one freshly
+/// compiled, with no GLSL re-parsing on the loading side.
+#[deriving(Clone, PartialEq, Show)]
+pub struct CachedProgram {
+    /// The `GL_PROGRAM_BINARY_FORMAT` driver-reported format enum, required
+    /// unchanged by `glProgramBinary` to reload the blob.
+    pub format: u32,
+    /// The opaque blob written by `glGetProgramBinary`.
+    pub binary: Vec<u8>,
+    /// The program's reflection data (uniform/block/texture names, types
+    /// and variable indices), as it was at link time.
+    pub info: s::ProgramInfo,
+}
+
 /// Variable index of a uniform.
 pub type VarUniform = u16;
 
@@ -81,6 +143,12 @@ pub trait Program {
     fn fill_params(&self, ParamValues);
 }
 
+// TODO(chunk0-1/chunk0-2): `ErrorUniformType` below assumes `device::shade`
+// already has a `UniformType` enum describing a uniform's declared base
+// type and vector/matrix dimension, and that `UniformVar.base_type` is
+// typed with it; `value_uniform_type` and chunk0-2's `UniformType::*Array`
+// variants assume the same. None of that is defined in this checkout - it
+// has to land as a matching `device` crate change for this to compile.
 /// An error type on either the parameter storage or the program side
 #[deriving(Clone, PartialEq, Show)]
 pub enum ParameterError {
@@ -92,8 +160,94 @@ pub enum ParameterError {
     ErrorBlock(String),
     /// Error with the named texture.
     ErrorTexture(String),
+    /// The named uniform is provided, but its value has a different type
+    /// (base type or vector/matrix dimension) than the shader declares.
+    ErrorUniformType(String, s::UniformValue, s::UniformType),
+    /// The shader declares a uniform by this name, but the dictionary has
+    /// no cell for it.
+    ErrorUniformMissing(String),
+    /// The shader declares a uniform block by this name, but the dictionary
+    /// has no cell for it.
+    ErrorBlockMissing(String),
+    /// The shader declares a texture by this name, but the dictionary has
+    /// no cell for it.
+    ErrorTextureMissing(String),
+    /// A `SemanticProgram` needs this uniform addressed by a semantic, but
+    /// the shader never declared one for it (as opposed to declaring one
+    /// that has no matching cell, see `ErrorUniformMissing`).
+    ErrorUniformSemantic(String),
+    /// Same as `ErrorUniformSemantic`, for a uniform block.
+    ErrorBlockSemantic(String),
+    /// Same as `ErrorUniformSemantic`, for a texture.
+    ErrorTextureSemantic(String),
+}
+
+/// Get the declared uniform type of a runtime value, so it can be compared
+/// against what a program expects for a given name.
+fn value_uniform_type(value: &s::UniformValue) -> s::UniformType {
+    match *value {
+        s::ValueI32(_) => s::UniformType::I32,
+        s::ValueU32(_) => s::UniformType::U32,
+        s::ValueF32(_) => s::UniformType::F32,
+        s::ValueBool(_) => s::UniformType::Bool,
+        s::ValueI32Vector2(_) => s::UniformType::I32Vector2,
+        s::ValueI32Vector3(_) => s::UniformType::I32Vector3,
+        s::ValueI32Vector4(_) => s::UniformType::I32Vector4,
+        s::ValueU32Vector2(_) => s::UniformType::U32Vector2,
+        s::ValueU32Vector3(_) => s::UniformType::U32Vector3,
+        s::ValueU32Vector4(_) => s::UniformType::U32Vector4,
+        s::ValueF32Vector2(_) => s::UniformType::F32Vector2,
+        s::ValueF32Vector3(_) => s::UniformType::F32Vector3,
+        s::ValueF32Vector4(_) => s::UniformType::F32Vector4,
+        s::ValueBoolVector2(_) => s::UniformType::BoolVector2,
+        s::ValueBoolVector3(_) => s::UniformType::BoolVector3,
+        s::ValueBoolVector4(_) => s::UniformType::BoolVector4,
+        s::ValueF32Matrix2(_) => s::UniformType::F32Matrix2,
+        s::ValueF32Matrix3(_) => s::UniformType::F32Matrix3,
+        s::ValueF32Matrix4(_) => s::UniformType::F32Matrix4,
+        s::ValueI32Array(ref v) => s::UniformType::I32Array(v.len()),
+        s::ValueF32Vector4Array(ref v) => s::UniformType::F32Vector4Array(v.len()),
+        s::ValueF32Matrix4Array(ref v) => s::UniformType::F32Matrix4Array(v.len()),
+    }
 }
 
+/// Resolve every uniform a program declares to a dictionary index,
+/// type-checking the dictionary's current value against what the shader
+/// expects. `$resolve` maps a declared uniform to either a dictionary index
+/// or the specific `ParameterError` that explains why it can't be resolved
+/// (no cell by that name, no cell for its semantic, no semantic at all,
+/// ...) so `DictionaryProgram::connect` and `SemanticProgram::connect` can
+/// share this loop while still reporting distinct failure modes. `$value_at`
+/// reads the current value at a resolved index.
+macro_rules! link_uniforms(
+    ($vars:expr, $resolve:expr, $value_at:expr) => ({
+        let mut ids = Vec::with_capacity($vars.len());
+        for var in $vars.iter() {
+            let id = try!($resolve(var));
+            let value = $value_at(id);
+            let found_type = value_uniform_type(&value);
+            if found_type != var.base_type {
+                return Err(ErrorUniformType(var.name.clone(), value, var.base_type.clone()));
+            }
+            ids.push(id);
+        }
+        ids
+    });
+)
+
+/// Resolve every block/texture a program declares to a dictionary index.
+/// Same sharing as `link_uniforms!`, minus the type check (blocks and
+/// textures aren't typed the way uniforms are).
+macro_rules! link_ids(
+    ($vars:expr, $resolve:expr) => ({
+        let mut ids = Vec::with_capacity($vars.len());
+        for var in $vars.iter() {
+            ids.push(try!($resolve(var)));
+        }
+        ids
+    });
+)
+
 /// Abstracts the shader parameter structure, generated by the `shader_param` attribute
 pub trait ShaderParam<L> {
     /// Creates a new link, self is passed as a workaround for Rust to not be lost in generics
@@ -192,12 +346,20 @@ impl<'a> Program for &'a EmptyProgram {
 }
 
 
-/// A named cell containing arbitrary value
+/// A named cell containing arbitrary value.
+///
+/// `value` is a `RefCell`, not a `Cell`: the array-valued `UniformValue`
+/// variants hold an `Rc<Vec<_>>`, which isn't `Copy`, so reading it
+/// needs `borrow().clone()` instead of `get()`. That means a live
+/// `borrow_mut()` on the same cell - e.g. updating a dictionary value from
+/// a callback invoked in the middle of a draw - will panic where the old
+/// `Cell` API could never fail. Don't hold a mutable borrow across a draw
+/// call.
 pub struct NamedCell<T> {
     /// Name
     pub name: String,
     /// Value
-    pub value: Cell<T>,
+    pub value: RefCell<T>,
 }
 
 /// A dictionary of parameters, meant to be shared between different programs
@@ -229,21 +391,29 @@ impl DictionaryProgram {
     /// Connect a shader program with a parameter structure
     pub fn connect(prog: ProgramHandle, data: Rc<ParamDictionary>)
                    -> Result<DictionaryProgram, ParameterError> {
-        //TODO: proper error checks
-        let link = ParamDictionaryLink {
-            uniforms: prog.get_info().uniforms.iter().map(|var|
-                data.uniforms.iter().position(|c| c.name == var.name).unwrap()
-            ).collect(),
-            blocks: prog.get_info().blocks.iter().map(|var|
-                data.blocks  .iter().position(|c| c.name == var.name).unwrap()
-            ).collect(),
-            textures: prog.get_info().textures.iter().map(|var|
-                data.textures.iter().position(|c| c.name == var.name).unwrap()
-            ).collect(),
-        };
+        let uniforms = link_uniforms!(prog.get_info().uniforms,
+            |var: &s::UniformVar| match data.uniforms.iter().position(|c| c.name == var.name) {
+                Some(id) => Ok(id),
+                None => Err(ErrorUniformMissing(var.name.clone())),
+            },
+            |id: uint| data.uniforms[id].value.borrow().clone());
+        let blocks = link_ids!(prog.get_info().blocks,
+            |var: &s::BlockVar| match data.blocks.iter().position(|c| c.name == var.name) {
+                Some(id) => Ok(id),
+                None => Err(ErrorBlockMissing(var.name.clone())),
+            });
+        let textures = link_ids!(prog.get_info().textures,
+            |var: &s::TextureVar| match data.textures.iter().position(|c| c.name == var.name) {
+                Some(id) => Ok(id),
+                None => Err(ErrorTextureMissing(var.name.clone())),
+            });
         Ok(DictionaryProgram {
             program: prog,
-            link: link,
+            link: ParamDictionaryLink {
+                uniforms: uniforms,
+                blocks: blocks,
+                textures: textures,
+            },
             data: data,
         })
     }
@@ -256,13 +426,134 @@ impl<'a> Program for &'a DictionaryProgram {
 
     fn fill_params(&self, params: ParamValues) {
         for (&id, var) in self.link.uniforms.iter().zip(params.uniforms.mut_iter()) {
-            *var = Some(self.data.uniforms[id].value.get());
+            *var = Some(self.data.uniforms[id].value.borrow().clone());
+        }
+        for (&id, var) in self.link.blocks.iter().zip(params.blocks.mut_iter()) {
+            *var = Some(self.data.blocks[id].value.borrow().clone());
+        }
+        for (&id, var) in self.link.textures.iter().zip(params.textures.mut_iter()) {
+            *var = Some(self.data.textures[id].value.borrow().clone());
+        }
+    }
+}
+
+/// A stable token identifying a uniform's role (the model-view matrix, the
+/// light count, ...) independent of whatever name a particular shader
+/// happens to declare it under. Declared once as a `static` and shared by
+/// every program that wants to be driven by the same semantic dictionary.
+///
+/// This has to live in `device::shade`, not here: `s::UniformVar` (and
+/// `BlockVar`/`TextureVar`) carry an `Option<UniformSemantic>` field so
+/// `SemanticProgram::connect` can match a shader's declared variable
+/// against a dictionary cell, and `device` is the crate `render` depends
+/// on, not the other way around — a type defined in `render::shade` can't
+/// be named by a `device::shade` field without a dependency cycle. Aliased
+/// here so callers can write `shade::UniformSemantic`.
+pub type UniformSemantic = s::UniformSemantic;
+
+/// A cell addressed by `UniformSemantic` rather than by shader name.
+///
+/// Same caveat as `NamedCell`: `value` is a `RefCell`, so a live
+/// `borrow_mut()` held across a draw call will panic on the `borrow()` in
+/// `SemanticProgram::fill_params`/`connect`.
+pub struct SemanticCell<T> {
+    /// Semantic this cell provides.
+    pub semantic: UniformSemantic,
+    /// Value
+    pub value: RefCell<T>,
+}
+
+/// A dictionary of parameters addressed by `UniformSemantic`, meant to be
+/// shared across many programs with unrelated uniform layouts, e.g. one
+/// store of camera matrices and lights driving every linked program in a
+/// scene.
+pub struct SemanticDictionary {
+    /// Uniform dictionary
+    pub uniforms: Vec<SemanticCell<s::UniformValue>>,
+    /// Block dictionary
+    pub blocks: Vec<SemanticCell<RawBufferHandle>>,
+    /// Texture dictionary
+    pub textures: Vec<SemanticCell<TextureParam>>,
+}
+
+/// An associated link structure for `SemanticDictionary`. Resolved once at
+/// `connect` time, so `fill_params` never has to look anything up by name
+/// or semantic again, only by position.
+pub struct SemanticDictionaryLink {
+    uniforms: Vec<uint>,
+    blocks: Vec<uint>,
+    textures: Vec<uint>,
+}
+
+/// A shader program whose parameters are supplied by a `SemanticDictionary`
+/// instead of by name.
+pub struct SemanticProgram {
+    program: ProgramHandle,
+    link: SemanticDictionaryLink,
+    data: Rc<SemanticDictionary>,
+}
+
+impl SemanticProgram {
+    /// Connect a shader program with a semantic parameter dictionary.
+    ///
+    /// A variable the shader never tagged with a semantic at all is a
+    /// different failure from one that has a semantic with no matching
+    /// dictionary cell, so the two are reported as distinct errors
+    /// (`ErrorUniformSemantic` vs. `ErrorUniformMissing`, and likewise for
+    /// blocks/textures).
+    pub fn connect(prog: ProgramHandle, data: Rc<SemanticDictionary>)
+                   -> Result<SemanticProgram, ParameterError> {
+        let uniforms = link_uniforms!(prog.get_info().uniforms,
+            |var: &s::UniformVar| match var.semantic {
+                None => Err(ErrorUniformSemantic(var.name.clone())),
+                Some(sem) => match data.uniforms.iter().position(|c| c.semantic == sem) {
+                    Some(id) => Ok(id),
+                    None => Err(ErrorUniformMissing(var.name.clone())),
+                },
+            },
+            |id: uint| data.uniforms[id].value.borrow().clone());
+        let blocks = link_ids!(prog.get_info().blocks,
+            |var: &s::BlockVar| match var.semantic {
+                None => Err(ErrorBlockSemantic(var.name.clone())),
+                Some(sem) => match data.blocks.iter().position(|c| c.semantic == sem) {
+                    Some(id) => Ok(id),
+                    None => Err(ErrorBlockMissing(var.name.clone())),
+                },
+            });
+        let textures = link_ids!(prog.get_info().textures,
+            |var: &s::TextureVar| match var.semantic {
+                None => Err(ErrorTextureSemantic(var.name.clone())),
+                Some(sem) => match data.textures.iter().position(|c| c.semantic == sem) {
+                    Some(id) => Ok(id),
+                    None => Err(ErrorTextureMissing(var.name.clone())),
+                },
+            });
+        Ok(SemanticProgram {
+            program: prog,
+            link: SemanticDictionaryLink {
+                uniforms: uniforms,
+                blocks: blocks,
+                textures: textures,
+            },
+            data: data,
+        })
+    }
+}
+
+impl<'a> Program for &'a SemanticProgram {
+    fn get_handle(&self) -> &ProgramHandle {
+        &self.program
+    }
+
+    fn fill_params(&self, params: ParamValues) {
+        for (&id, var) in self.link.uniforms.iter().zip(params.uniforms.mut_iter()) {
+            *var = Some(self.data.uniforms[id].value.borrow().clone());
         }
         for (&id, var) in self.link.blocks.iter().zip(params.blocks.mut_iter()) {
-            *var = Some(self.data.blocks[id].value.get());
+            *var = Some(self.data.blocks[id].value.borrow().clone());
         }
         for (&id, var) in self.link.textures.iter().zip(params.textures.mut_iter()) {
-            *var = Some(self.data.textures[id].value.get());
+            *var = Some(self.data.textures[id].value.borrow().clone());
         }
     }
 }